@@ -1,9 +1,11 @@
 use parse_regex::RegExParser;
-use parse_regex::RegEx::{self, Or, Repetition, Sequence, Terminal};
-use automata::NFA;
-use automata::Transition::{Input, Epsilon};
+use parse_regex::RegEx::{self, Or, Repetition, Sequence, Terminal, Class, Any};
+use automata::{NFA, DFA, Transition};
+use automata::Transition::{Input, Epsilon, Range, Push, Pop};
 
+use std::cmp::Ordering;
 use std::collections::{HashMap, HashSet};
+use std::io::Read;
 
 macro_rules! set {
     ($($elem:expr),*) => ({
@@ -23,10 +25,109 @@ macro_rules! map {
 
 type State = usize;
 
+/// A 1-indexed line/column paired with a 0-indexed byte offset into the
+/// original input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize
+}
+
+impl Position {
+    fn start() -> Position {
+        Position { offset: 0, line: 1, column: 1 }
+    }
+
+    // Advances this position over `chars`, recomputed from scratch each time
+    // rather than tracked incrementally char-by-char, so it stays correct
+    // even when maximal munch scanned ahead and then backtracked to an
+    // earlier accepting position.
+    fn advance(&self, chars: &[char]) -> Position {
+        let mut p = *self;
+        for &c in chars {
+            p.offset += c.len_utf8();
+            if c == '\n' {
+                p.line += 1;
+                p.column = 1;
+            } else {
+                p.column += 1;
+            }
+        }
+        p
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: Position,
+    pub end: Position
+}
+
+pub const DEFAULT_GROUP: &'static str = "default";
+
 pub struct Lexer<T> {
     nfa_builder: NFABuilder,
+    groups: HashMap<String, Group<T>>,
+    next_rule_id: usize
+}
+
+struct Group<T> {
     nfas: Vec<NFA<State>>,
-    tok_map: HashMap<State, T>
+    tok_map: HashMap<State, Rule<T>>,
+    // Balanced/nested rules (e.g. nested comments) can't be folded into the
+    // group's merged DFA: taking a Push/Pop edge has a side effect that the
+    // subset construction can't account for, so each is matched by directly
+    // simulating its own NFA against a runtime symbol stack.
+    nested: Vec<(NFA<State>, Rule<T>)>
+}
+
+impl<T> Group<T> {
+    fn new() -> Group<T> {
+        Group { nfas: Vec::new(), tok_map: HashMap::new(), nested: Vec::new() }
+    }
+}
+
+// What happens to the group stack when a rule fires. `Stay` is the behavior
+// every rule had before groups existed.
+#[derive(Clone)]
+pub enum GroupAction {
+    Stay,
+    Push(String),
+    Pop
+}
+
+// Wraps a token with the insertion order of the rule that produced it, so that
+// when the subset construction merges several rules' accept states into one
+// DFA state we can still recover "first rule wins" by picking the lowest id.
+//
+// Contract with the automata crate: when `NFA::into_dfa` merges several NFA
+// accept states (one per rule) into a single DFA state -- e.g. "if" and
+// "[a-zA-Z]+" both accepting right after "if" -- it is expected to resolve
+// the tie by keeping the `Rule` that compares lowest via this `Ord` impl,
+// i.e. the earliest-added rule. That merge itself happens inside the
+// automata crate, which this repo depends on but does not vendor, so it
+// can't be verified here; `tests/cases/rule_priority.toml` pins the
+// expected outward behavior for whenever this tree can be built.
+#[derive(Clone)]
+struct Rule<T> {
+    id: usize,
+    token: T,
+    action: GroupAction
+}
+
+impl<T> PartialEq for Rule<T> {
+    fn eq(&self, other: &Rule<T>) -> bool { self.id == other.id }
+}
+
+impl<T> Eq for Rule<T> {}
+
+impl<T> PartialOrd for Rule<T> {
+    fn partial_cmp(&self, other: &Rule<T>) -> Option<Ordering> { self.id.partial_cmp(&other.id) }
+}
+
+impl<T> Ord for Rule<T> {
+    fn cmp(&self, other: &Rule<T>) -> Ordering { self.id.cmp(&other.id) }
 }
 
 pub struct NFABuilder {
@@ -73,7 +174,9 @@ impl NFABuilder {
                 self.construct_sequence_nfa(&v)
             },
             Repetition(box r) => self.construct_repetition_nfa(r),
-            Terminal(c) => self.construct_terminal_nfa(c)
+            Terminal(c) => self.construct_terminal_nfa(c),
+            Class(low, high) => self.construct_range_nfa(low, high),
+            Any => self.construct_range_nfa('\u{0}', ::std::char::MAX)
         }
     }
 
@@ -144,6 +247,52 @@ impl NFABuilder {
         NFA::new(start, set!(end), map!((start, Input(c)) => set!(end)))
     }
 
+    // A character class or wildcard becomes a single Range edge rather than an
+    // Or of every matching char, so the subset construction only has to split
+    // on the low/high cut points of this range instead of one state per char.
+    fn construct_range_nfa(&mut self, low: char, high: char) -> NFA<State> {
+        let start = self.get_id();
+        let end = self.get_id();
+        NFA::new(start, set!(end), map!((start, Range { low: low, high: high }) => set!(end)))
+    }
+
+    // Builds a PDA-flavored NFA matching `open`, any number of arbitrary or
+    // (recursively) nested `open ... close` spans, then `close`, tracking
+    // nesting with `sym` on the runtime stack. The only accept state is the
+    // shared "body" state; it is only a real match once the stack it was
+    // reached with is empty again, which `run_pushdown` enforces.
+    fn construct_nested_nfa(&mut self, open: &str, close: &str, sym: char) -> NFA<State> {
+        let mut m = HashMap::new();
+        let (open_start, open_end) = self.chain_literal(open, &mut m);
+        let (close_start, close_end) = self.chain_literal(close, &mut m);
+
+        let start = self.get_id();
+        let pushed = self.get_id();
+        let body = self.get_id();
+
+        m.insert((start, Epsilon), set!(open_start));
+        m.insert((open_end, Push(sym)), set!(pushed));
+        m.insert((pushed, Epsilon), set!(body));
+        m.insert((body, Range { low: '\u{0}', high: ::std::char::MAX }), set!(body));
+        m.insert((body, Epsilon), set!(open_start, close_start));
+        m.insert((close_end, Pop(sym)), set!(body));
+
+        NFA::new(start, set!(body), m)
+    }
+
+    // Chains one Input edge per char of `s` into `m`, returning the chain's
+    // start and end states.
+    fn chain_literal(&mut self, s: &str, m: &mut HashMap<(State, Transition), HashSet<State>>) -> (State, State) {
+        let start = self.get_id();
+        let mut cur = start;
+        for c in s.chars() {
+            let next = self.get_id();
+            m.insert((cur, Input(c)), set!(next));
+            cur = next;
+        }
+        (start, cur)
+    }
+
     fn get_id(&mut self) -> State {
         let id = self.state_id;
         self.state_id += 1;
@@ -153,52 +302,569 @@ impl NFABuilder {
 
 impl<T: Clone> Lexer<T> {
     fn new() -> Lexer<T> {
-        Lexer { nfa_builder: NFABuilder::new(), nfas: Vec::new(), tok_map: HashMap::new() }
+        Lexer {
+            nfa_builder: NFABuilder::new(),
+            groups: HashMap::new(),
+            next_rule_id: 0
+        }
     }
 
     pub fn add_token(&mut self, regex: &str, token: T) {
+        self.add_rule(DEFAULT_GROUP, regex, token, GroupAction::Stay)
+    }
+
+    /// Adds a rule that is only active while `group` is on top of the group
+    /// stack, e.g. a `string` group's rules for matching the body of a string
+    /// literal once a `"` has pushed us into it.
+    pub fn add_token_in(&mut self, group: &str, regex: &str, token: T) {
+        self.add_rule(group, regex, token, GroupAction::Stay)
+    }
+
+    /// Like `add_token_in`, but matching this rule also pushes `target` onto
+    /// the group stack, making its rules the active ones from then on.
+    pub fn add_token_pushing(&mut self, group: &str, regex: &str, token: T, target: &str) {
+        self.add_rule(group, regex, token, GroupAction::Push(target.to_string()))
+    }
+
+    /// Like `add_token_in`, but matching this rule pops the group stack,
+    /// returning to whichever group was active before, e.g. a closing quote
+    /// returning from `string` to `default`.
+    pub fn add_token_popping(&mut self, group: &str, regex: &str, token: T) {
+        self.add_rule(group, regex, token, GroupAction::Pop)
+    }
+
+    /// Adds a rule matching `open`, balanced nesting of `open`/`close` pairs,
+    /// then the matching `close` -- e.g. `add_nested_in("default", "/*",
+    /// "*/", '*', Token::COMMENT)` for C-style nested block comments. This
+    /// can't be expressed as a regular expression, so unlike `add_token_in`
+    /// it is matched by directly simulating a small pushdown automaton
+    /// rather than folding into the group's merged DFA.
+    pub fn add_nested_in(&mut self, group: &str, open: &str, close: &str, sym: char, token: T) {
+        let nfa = self.nfa_builder.construct_nested_nfa(open, close, sym);
+
+        let id = self.next_rule_id;
+        self.next_rule_id += 1;
+
+        let g = match self.groups.entry(group.to_string()).get() {
+            Ok(g) => g,
+            Err(e) => e.insert(Group::new())
+        };
+        g.nested.push((nfa, Rule { id: id, token: token, action: GroupAction::Stay }));
+    }
+
+    fn add_rule(&mut self, group: &str, regex: &str, token: T, action: GroupAction) {
         let mut p = RegExParser::new(regex.to_string());
         let nfa = match p.parse() {
             Ok(r) => self.nfa_builder.regex_to_nfa(r),
             Err(e) => panic!("Error in regex: {}", e)
         };
         let accept_states: Vec<_> = nfa.get_accept_states().clone().into_iter().collect();
-        self.nfas.push(nfa);
         assert!(accept_states.len() == 1);
-        self.tok_map.insert(accept_states[0], token);
-    }
 
-    pub fn lex(&mut self, s: &str) -> Vec<T> {
-        let Lexer { ref mut nfa_builder, ref nfas, ref tok_map } = *self;
+        let id = self.next_rule_id;
+        self.next_rule_id += 1;
 
-        let nfa = if nfas.len() > 0 {
-            nfa_builder.merge_nfas(nfas)
-        } else {
-            return Vec::new();
+        let g = match self.groups.entry(group.to_string()).get() {
+            Ok(g) => g,
+            Err(e) => e.insert(Group::new())
         };
+        g.tok_map.insert(accept_states[0], Rule { id: id, token: token, action: action });
+        g.nfas.push(nfa);
+    }
+
+    // Merges each group's rules into one DFA per group, as `lex` and
+    // `tokens` both need.
+    fn compile(&mut self) -> HashMap<String, (DFA<State>, HashMap<State, Rule<T>>)> {
+        let Lexer { ref mut nfa_builder, ref groups, .. } = *self;
+
+        let mut compiled = HashMap::new();
+        for (name, group) in groups.iter() {
+            if group.nfas.len() == 0 {
+                continue;
+            }
+            let nfa = nfa_builder.merge_nfas(&group.nfas);
+            let (dfa, tok_map) = nfa.into_dfa(&group.tok_map);
+            compiled.insert(name.clone(), (dfa, tok_map));
+        }
+        compiled
+    }
 
-        let (dfa, tok_map) = nfa.into_dfa(tok_map);
+    /// Scans `s` left to right with maximal munch, one merged DFA per group,
+    /// pairing each emitted token with the `Span` it covered in `s`. The
+    /// group stack starts at `DEFAULT_GROUP`; whichever group is on top picks
+    /// the DFA used for the next match, and a matched rule's action may push
+    /// or pop the stack before scanning continues from there.
+    pub fn lex_spanned(&mut self, s: &str) -> Vec<(T, Span)> {
+        let compiled = self.compile();
+        let groups = &self.groups;
 
+        let chars: Vec<char> = s.chars().collect();
+        let mut stack = vec![DEFAULT_GROUP.to_string()];
         let mut toks = Vec::new();
-        let mut tok = None;
-        for state in dfa.iter(s.chars().collect()) {
-            if dfa.get_accept_states().contains(state) {
-                let t: T = (*tok_map.get(state).unwrap()).clone();
-                tok = Some(t);
+        let mut i = 0;
+        let mut pos = Position::start();
+
+        while i < chars.len() {
+            let group_name = stack.last().unwrap().clone();
+            let mut best: Option<(usize, Rule<T>)> = None;
+
+            if let Some(&(ref dfa, ref tok_map)) = compiled.get(&group_name) {
+                let mut state = *dfa.get_start_state();
+                let mut p = i;
+
+                loop {
+                    if dfa.get_accept_states().contains(&state) {
+                        best = better(best, Some((p, tok_map.get(&state).unwrap().clone())));
+                    }
+                    if p >= chars.len() {
+                        break;
+                    }
+                    match dfa.step(&state, chars[p]) {
+                        Some(next) => { state = next; p += 1; }
+                        None => break
+                    }
+                }
+            }
+
+            if let Some(group) = groups.get(&group_name) {
+                for &(ref nfa, ref rule) in group.nested.iter() {
+                    if let Some(end) = run_pushdown(nfa, &chars, i) {
+                        best = better(best, Some((end, rule.clone())));
+                    }
+                }
+            }
+
+            match best {
+                Some((end, rule)) => {
+                    let end_pos = pos.advance(&chars[i..end]);
+                    toks.push((rule.token, Span { start: pos, end: end_pos }));
+                    pos = end_pos;
+
+                    if end > i {
+                        i = end;
+                    } else {
+                        // A nullable rule (e.g. `a*`) can match zero chars at
+                        // `i`, which would otherwise leave `i` unchanged
+                        // forever. Skip one char so scanning always makes
+                        // progress; the skipped char is simply not part of
+                        // this token, same as the no-match case below.
+                        pos = pos.advance(&chars[i..i + 1]);
+                        i += 1;
+                    }
+
+                    match rule.action {
+                        GroupAction::Push(group) => stack.push(group),
+                        GroupAction::Pop => { if stack.len() > 1 { stack.pop(); } },
+                        GroupAction::Stay => {}
+                    }
+                }
+                None => {
+                    pos = pos.advance(&chars[i..i + 1]);
+                    i += 1;
+                }
             }
         }
-        if let Some(t) = tok {
-            toks.push(t);
-        };
         toks
     }
+
+    /// Convenience wrapper over `lex_spanned` for callers that don't need
+    /// span information.
+    pub fn lex(&mut self, s: &str) -> Vec<T> {
+        self.lex_spanned(s).into_iter().map(|(tok, _)| tok).collect()
+    }
+
+    /// Like `lex`, but drives the DFAs from any char iterator instead of a
+    /// fully materialized `&str`, so memory stays bounded by the longest
+    /// single token rather than the whole input. Unlike `lex`, a position
+    /// with no accepting match anywhere ends the stream with a `LexError`
+    /// instead of silently skipping a char. Pushdown (`add_nested_in`) rules
+    /// aren't matched by this path yet.
+    pub fn tokens<I: CharSource>(&mut self, iter: I) -> TokenStream<T, I> {
+        TokenStream {
+            compiled: self.compile(),
+            buf: RewindBuffer::new(iter),
+            stack: vec![DEFAULT_GROUP.to_string()],
+            pos: Position::start(),
+            done: false
+        }
+    }
+
+    /// Convenience wrapper over `tokens` for any `io::Read`, decoding it as
+    /// UTF-8 on demand. A read failure or invalid UTF-8 from `reader` ends
+    /// the stream with a `Some(Err(LexError::Io(..)))` or
+    /// `Some(Err(LexError::InvalidUtf8))`, rather than looking like a clean
+    /// EOF.
+    pub fn tokens_from_read<R: Read>(&mut self, reader: R) -> TokenStream<T, CharsFromRead<R>> {
+        self.tokens(CharsFromRead::new(reader))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum LexError {
+    NoMatch,
+    /// The underlying reader returned an error mid-stream; the message is
+    /// `io::Error`'s Display output, kept as a String since io::Error itself
+    /// isn't Clone.
+    Io(String),
+    /// The reader produced bytes that aren't valid UTF-8, including a
+    /// multi-byte sequence truncated by EOF.
+    InvalidUtf8
+}
+
+/// A char iterator that can report *why* it stopped early -- an I/O error or
+/// invalid encoding -- rather than looking identical to a clean end of input.
+/// Ordinary char iterators (e.g. `str::Chars`) can never fail this way, so
+/// they just report `None`.
+pub trait CharSource: Iterator<Item = char> {
+    fn error(&self) -> Option<LexError> { None }
+}
+
+impl<'a> CharSource for ::std::str::Chars<'a> {
+    fn error(&self) -> Option<LexError> { None }
+}
+
+// Pulls chars from `iter` on demand and buffers only what maximal munch
+// might still need to rewind to (back to the last accepting position of the
+// token currently being scanned); `discard_before` drops everything the
+// scanner has committed past so the buffer can't grow unboundedly.
+struct RewindBuffer<I> {
+    iter: I,
+    buf: Vec<char>
+}
+
+impl<I: CharSource> RewindBuffer<I> {
+    fn new(iter: I) -> RewindBuffer<I> {
+        RewindBuffer { iter: iter, buf: Vec::new() }
+    }
+
+    fn get(&mut self, i: usize) -> Option<char> {
+        while self.buf.len() <= i {
+            match self.iter.next() {
+                Some(c) => self.buf.push(c),
+                None => return None
+            }
+        }
+        Some(self.buf[i])
+    }
+
+    fn discard_before(&mut self, upto: usize) {
+        self.buf.drain(0..upto);
+    }
+
+    fn slice(&self, end: usize) -> &[char] {
+        &self.buf[0..end]
+    }
+
+    fn error(&self) -> Option<LexError> {
+        self.iter.error()
+    }
+}
+
+/// Decodes an `io::Read` as UTF-8 one char at a time, so `Lexer::tokens` can
+/// drive a streaming reader the same way it drives any other char iterator.
+/// `Iterator::next` still returns `None` on any failure, since `Option<char>`
+/// has no room for an error -- call `error()` (via `CharSource`) afterwards
+/// to tell a genuine EOF apart from a failed read or invalid encoding.
+pub struct CharsFromRead<R> {
+    reader: R,
+    error: Option<LexError>
+}
+
+impl<R: Read> CharsFromRead<R> {
+    pub fn new(reader: R) -> CharsFromRead<R> {
+        CharsFromRead { reader: reader, error: None }
+    }
+}
+
+impl<R: Read> Iterator for CharsFromRead<R> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        if self.error.is_some() {
+            return None;
+        }
+
+        let mut buf = [0u8; 4];
+        match self.reader.read(&mut buf[0..1]) {
+            Ok(0) => return None,
+            Ok(_) => {}
+            Err(e) => { self.error = Some(LexError::Io(e.to_string())); return None; }
+        }
+
+        let len = match utf8_len(buf[0]) {
+            Some(len) => len,
+            None => { self.error = Some(LexError::InvalidUtf8); return None; }
+        };
+
+        for i in 1..len {
+            match self.reader.read(&mut buf[i..i + 1]) {
+                Ok(0) => { self.error = Some(LexError::InvalidUtf8); return None; }
+                Ok(_) => {}
+                Err(e) => { self.error = Some(LexError::Io(e.to_string())); return None; }
+            }
+        }
+
+        match ::std::str::from_utf8(&buf[0..len]) {
+            Ok(s) => s.chars().next(),
+            Err(_) => { self.error = Some(LexError::InvalidUtf8); None }
+        }
+    }
+}
+
+impl<R: Read> CharSource for CharsFromRead<R> {
+    fn error(&self) -> Option<LexError> {
+        self.error.clone()
+    }
+}
+
+// Classifies a UTF-8 lead byte into the total sequence length, or None if
+// it isn't a valid lead byte at all (a stray continuation byte 0x80-0xBF,
+// or one of the bytes 0xC0/0xC1/0xF5-0xFF that UTF-8 never uses).
+fn utf8_len(first_byte: u8) -> Option<usize> {
+    match first_byte {
+        0x00...0x7F => Some(1),
+        0xC2...0xDF => Some(2),
+        0xE0...0xEF => Some(3),
+        0xF0...0xF4 => Some(4),
+        _ => None
+    }
+}
+
+/// Iterator returned by `Lexer::tokens`/`Lexer::tokens_from_read`.
+pub struct TokenStream<T, I> {
+    compiled: HashMap<String, (DFA<State>, HashMap<State, Rule<T>>)>,
+    buf: RewindBuffer<I>,
+    stack: Vec<String>,
+    pos: Position,
+    done: bool
+}
+
+impl<T: Clone, I: CharSource> Iterator for TokenStream<T, I> {
+    type Item = Result<(T, Span), LexError>;
+
+    fn next(&mut self) -> Option<Result<(T, Span), LexError>> {
+        if self.done {
+            return None;
+        }
+        if self.buf.get(0).is_none() {
+            self.done = true;
+            // A source that failed partway through (I/O error, invalid
+            // UTF-8) looks identical to a clean EOF from here otherwise;
+            // surface the real reason if there is one.
+            return self.buf.error().map(Err);
+        }
+
+        let group_name = self.stack.last().unwrap().clone();
+        let &(ref dfa, ref tok_map) = match self.compiled.get(&group_name) {
+            Some(c) => c,
+            None => {
+                self.done = true;
+                return Some(Err(LexError::NoMatch));
+            }
+        };
+
+        let mut state = *dfa.get_start_state();
+        let mut pos = 0;
+        let mut last_accept: Option<(usize, Rule<T>)> = None;
+
+        loop {
+            if dfa.get_accept_states().contains(&state) {
+                last_accept = Some((pos, tok_map.get(&state).unwrap().clone()));
+            }
+            let c = match self.buf.get(pos) {
+                Some(c) => c,
+                None => break
+            };
+            match dfa.step(&state, c) {
+                Some(next) => { state = next; pos += 1; }
+                None => break
+            }
+        }
+
+        match last_accept {
+            Some((end, rule)) => {
+                let start_pos = self.pos;
+                let end_pos = start_pos.advance(self.buf.slice(end));
+
+                // A nullable rule can match zero chars, which would leave
+                // the buffer's front untouched and yield this same token
+                // forever. Discard one extra char in that case so the
+                // stream always advances; `end_pos` above still reflects
+                // the token's true (zero-width) span.
+                let discard = if end > 0 { end } else { 1 };
+                let new_pos = if discard == end {
+                    end_pos
+                } else {
+                    start_pos.advance(self.buf.slice(discard))
+                };
+                self.buf.discard_before(discard);
+                self.pos = new_pos;
+
+                match rule.action {
+                    GroupAction::Push(g) => self.stack.push(g),
+                    GroupAction::Pop => { if self.stack.len() > 1 { self.stack.pop(); } },
+                    GroupAction::Stay => {}
+                }
+                Some(Ok((rule.token, Span { start: start_pos, end: end_pos })))
+            }
+            None => {
+                self.done = true;
+                Some(Err(LexError::NoMatch))
+            }
+        }
+    }
+}
+
+// Picks the longer of two match candidates, breaking ties by rule priority
+// (the `Ord` impl on `Rule` compares by insertion id).
+fn better<T>(a: Option<(usize, Rule<T>)>, b: Option<(usize, Rule<T>)>) -> Option<(usize, Rule<T>)> {
+    match (a, b) {
+        (None, b) => b,
+        (a, None) => a,
+        (Some((pos_a, rule_a)), Some((pos_b, rule_b))) => {
+            if pos_b > pos_a || (pos_b == pos_a && rule_b < rule_a) {
+                Some((pos_b, rule_b))
+            } else {
+                Some((pos_a, rule_a))
+            }
+        }
+    }
+}
+
+// Simulates `nfa` (as built by `construct_nested_nfa`) against `chars` from
+// `start`, tracking one symbol stack per live configuration since different
+// paths through Push/Pop edges can diverge. Re-closes over Epsilon/Push/Pop
+// edges after every character, pruning any configuration whose Pop hit a
+// Mismatch or an EmptyStack. Returns the furthest position at which a live
+// configuration was both in an accept state and held an empty stack.
+fn run_pushdown(nfa: &NFA<State>, chars: &[char], start: usize) -> Option<usize> {
+    let transitions = nfa.get_transitions();
+    let accept_states = nfa.get_accept_states();
+
+    let mut configs = close_pushdown_epsilon(transitions, vec![(*nfa.get_start_state(), Vec::new())]);
+    let mut pos = start;
+    let mut last_accept = None;
+
+    loop {
+        // A configuration that is both in an accept state and holds an
+        // empty stack has matched one complete balanced span. Record it,
+        // but don't let it keep going: `body` is shared across every
+        // nesting depth and still has its content self-loop, so leaving a
+        // finished config alive would let it silently swallow whatever
+        // comes after the real closing delimiter (e.g. "/* a */X" matching
+        // "X" into the same token). Any other, still-nested configuration
+        // is left alone and keeps scanning.
+        let mut live = Vec::with_capacity(configs.len());
+        for (state, sym_stack) in configs.into_iter() {
+            if accept_states.contains(&state) && sym_stack.is_empty() {
+                last_accept = Some(pos);
+            } else {
+                live.push((state, sym_stack));
+            }
+        }
+        configs = live;
+
+        if pos >= chars.len() || configs.is_empty() {
+            break;
+        }
+
+        let c = chars[pos];
+        let mut next = Vec::new();
+        for &(state, ref sym_stack) in configs.iter() {
+            for (trans, targets) in transitions.iter() {
+                let &(from, ref t) = trans;
+                if from != state {
+                    continue;
+                }
+                let consumed = match *t {
+                    Input(ch) => ch == c,
+                    Range { low, high } => c >= low && c <= high,
+                    _ => false
+                };
+                if consumed {
+                    for target in targets.iter() {
+                        next.push((*target, sym_stack.clone()));
+                    }
+                }
+            }
+        }
+
+        if next.len() == 0 {
+            break;
+        }
+        configs = close_pushdown_epsilon(transitions, next);
+        pos += 1;
+    }
+
+    last_accept
+}
+
+fn close_pushdown_epsilon(transitions: &HashMap<(State, Transition), HashSet<State>>,
+                           configs: Vec<(State, Vec<char>)>) -> Vec<(State, Vec<char>)> {
+    let mut seen = HashSet::new();
+    let mut pending = configs;
+    let mut closure = Vec::new();
+
+    while let Some((state, sym_stack)) = pending.pop() {
+        if !seen.insert((state, sym_stack.clone())) {
+            continue;
+        }
+
+        for (trans, targets) in transitions.iter() {
+            let &(from, ref t) = trans;
+            if from != state {
+                continue;
+            }
+            match *t {
+                Epsilon => {
+                    for target in targets.iter() {
+                        pending.push((*target, sym_stack.clone()));
+                    }
+                },
+                Push(sym) => {
+                    let mut pushed = sym_stack.clone();
+                    pushed.push(sym);
+                    for target in targets.iter() {
+                        pending.push((*target, pushed.clone()));
+                    }
+                },
+                Pop(sym) => {
+                    let mut popped = sym_stack.clone();
+                    if popped.pop() == Some(sym) {
+                        for target in targets.iter() {
+                            pending.push((*target, popped.clone()));
+                        }
+                    }
+                    // Mismatch (popped something else) or EmptyStack (nothing
+                    // to pop): this branch simply dies out, same as a DFA
+                    // with no outgoing transition for the current input.
+                },
+                _ => {}
+            }
+        }
+
+        closure.push((state, sym_stack));
+    }
+
+    closure
 }
 
 #[cfg(test)]
 mod test {
-    use lexer::Lexer;
+    use lexer::{Lexer, Rule, GroupAction, CharsFromRead, CharSource, LexError};
 
-    #[derive(Debug, Clone)]
+    // Pure check of the tie-break Rule::Ord is supposed to provide (lower
+    // insertion id wins regardless of token value); the actual merge that
+    // relies on it happens in NFA::into_dfa, in the un-vendored automata
+    // crate, and can't be exercised from this tree.
+    #[test]
+    fn test_rule_priority_by_insertion_order() {
+        let earlier = Rule { id: 0, token: "IF", action: GroupAction::Stay };
+        let later = Rule { id: 1, token: "IDENT", action: GroupAction::Stay };
+        assert!(earlier < later);
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
     enum Token {
         IF,
         WHILE,
@@ -216,4 +882,177 @@ mod test {
             println!("{:?}", token);
         }
     }
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    enum StrToken {
+        QUOTE,
+        CHAR
+    }
+
+    #[test]
+    fn test_groups() {
+        let mut lexer = Lexer::new();
+        lexer.add_token_pushing("default", "\"", StrToken::QUOTE, "string");
+        lexer.add_token_popping("string", "\"", StrToken::QUOTE);
+        lexer.add_token_in("string", "a", StrToken::CHAR);
+
+        let toks = lexer.lex("\"a\"");
+        assert_eq!(toks, vec![StrToken::QUOTE, StrToken::CHAR, StrToken::QUOTE]);
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    enum CommentToken {
+        COMMENT
+    }
+
+    #[test]
+    fn test_nested_comment() {
+        let mut lexer = Lexer::new();
+        lexer.add_nested_in("default", "/*", "*/", '*', CommentToken::COMMENT);
+
+        let toks = lexer.lex("/* outer /* inner */ outer */");
+        assert_eq!(toks, vec![CommentToken::COMMENT]);
+    }
+
+    #[test]
+    fn test_nested_comment_stops_at_close() {
+        // Regression test: a finished (stack-empty) match must not keep
+        // consuming characters past its own closing delimiter.
+        let mut lexer = Lexer::new();
+        lexer.add_nested_in("default", "/*", "*/", '*', CommentToken::COMMENT);
+
+        let spans = lexer.lex_spanned("/* a */X");
+        assert_eq!(spans.len(), 1);
+        assert_eq!(spans[0].0, CommentToken::COMMENT);
+        assert_eq!(spans[0].1.end.offset, 7, "comment must end before 'X'");
+    }
+
+    #[test]
+    fn test_adjacent_comments_do_not_merge() {
+        let mut lexer = Lexer::new();
+        lexer.add_nested_in("default", "/*", "*/", '*', CommentToken::COMMENT);
+
+        let toks = lexer.lex("/* a *//* b */");
+        assert_eq!(toks, vec![CommentToken::COMMENT, CommentToken::COMMENT]);
+    }
+
+    #[test]
+    fn test_tokens_streaming() {
+        let mut lexer = Lexer::new();
+        lexer.add_token("if", Token::IF);
+        lexer.add_token("while", Token::WHILE);
+        lexer.add_token("(0|1)|2", Token::NUM);
+
+        let toks: Vec<Token> = lexer.tokens("if while 0 1 2".chars())
+            .map(|r| r.expect("no lex errors expected").0)
+            .collect();
+        assert_eq!(toks, vec![Token::IF, Token::WHILE, Token::NUM, Token::NUM, Token::NUM]);
+    }
+
+    #[test]
+    fn test_chars_from_read_reports_invalid_utf8() {
+        let mut reader = CharsFromRead::new(&b"ab\xff"[..]);
+        assert_eq!(reader.next(), Some('a'));
+        assert_eq!(reader.next(), Some('b'));
+        assert_eq!(reader.next(), None);
+        match reader.error() {
+            Some(LexError::InvalidUtf8) => {}
+            other => panic!("expected InvalidUtf8, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn test_chars_from_read_clean_eof_has_no_error() {
+        let mut reader = CharsFromRead::new(&b"ab"[..]);
+        assert_eq!(reader.next(), Some('a'));
+        assert_eq!(reader.next(), Some('b'));
+        assert_eq!(reader.next(), None);
+        assert!(reader.error().is_none());
+    }
+
+    #[test]
+    fn test_chars_from_read_reports_truncated_sequence() {
+        // 0xE2 leads a 3-byte sequence, but only one continuation byte
+        // follows before EOF.
+        let mut reader = CharsFromRead::new(&[0xE2u8, 0x82][..]);
+        assert_eq!(reader.next(), None);
+        match reader.error() {
+            Some(LexError::InvalidUtf8) => {}
+            other => panic!("expected InvalidUtf8, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn test_spans() {
+        let mut lexer = Lexer::new();
+        lexer.add_token("if", Token::IF);
+        lexer.add_token("while", Token::WHILE);
+        lexer.add_token("(0|1)|2", Token::NUM);
+
+        let toks = lexer.lex_spanned("if\nwhile 2");
+        let positions: Vec<_> = toks.iter()
+            .map(|&(ref tok, span)| (tok.clone(), span.start.offset, span.start.line, span.start.column,
+                                      span.end.offset, span.end.line, span.end.column))
+            .collect();
+        assert_eq!(positions, vec![
+            (Token::IF, 0, 1, 1, 2, 1, 3),
+            (Token::WHILE, 3, 2, 1, 8, 2, 6),
+            (Token::NUM, 9, 2, 7, 10, 2, 8)
+        ]);
+    }
+
+    // A rule table, input, and expected token stream declared as TOML
+    // instead of a Rust function, so contributors can add a regression case
+    // for a tricky regex/lexer feature by dropping a file in tests/cases/
+    // rather than writing new Rust.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct LabeledToken(String);
+
+    #[test]
+    fn test_toml_conformance() {
+        let dir = ::std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/cases");
+        for entry in ::std::fs::read_dir(&dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().and_then(|e| e.to_str()) != Some("toml") {
+                continue;
+            }
+            run_toml_case(&path);
+        }
+    }
+
+    fn run_toml_case(path: &::std::path::Path) {
+        let mut src = String::new();
+        ::std::io::Read::read_to_string(&mut ::std::fs::File::open(path).unwrap(), &mut src).unwrap();
+        let doc = src.parse::<::toml::Value>().unwrap();
+        let table = doc.as_table().unwrap();
+
+        let mut lexer = Lexer::new();
+        for rule in table["rule"].as_slice().unwrap() {
+            let regex = rule["regex"].as_str().unwrap();
+            let token = rule["token"].as_str().unwrap().to_string();
+            lexer.add_token(regex, LabeledToken(token));
+        }
+
+        let input = table["input"].as_str().unwrap();
+        let actual = lexer.lex_spanned(input);
+
+        if table.get("should_not_match").and_then(|v| v.as_bool()).unwrap_or(false) {
+            assert!(actual.is_empty(), "{}: expected no tokens, got {:?}", path.display(), actual);
+            return;
+        }
+
+        let expect = table["expect"].as_slice().unwrap();
+        assert_eq!(actual.len(), expect.len(), "{}: token count", path.display());
+
+        for (i, &(ref tok, span)) in actual.iter().enumerate() {
+            let e = expect[i].as_table().unwrap();
+            assert_eq!(tok.0, e["token"].as_str().unwrap(), "{}: token {} label", path.display(), i);
+            if let Some(start) = e.get("start").and_then(|v| v.as_integer()) {
+                assert_eq!(span.start.offset as i64, start, "{}: token {} start", path.display(), i);
+            }
+            if let Some(end) = e.get("end").and_then(|v| v.as_integer()) {
+                assert_eq!(span.end.offset as i64, end, "{}: token {} end", path.display(), i);
+            }
+        }
+    }
 }
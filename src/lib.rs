@@ -3,6 +3,9 @@
 extern crate "parse-regex" as parse_regex;
 extern crate automata;
 
+#[cfg(test)]
+extern crate toml;
+
 pub mod lexer;
 
 pub use lexer::Lexer;